@@ -1,585 +1,739 @@
-use std::{env, fs};
-use std::collections::{HashMap};
-use std::fs::read;
-use std::path::{PathBuf};
+use std::collections::HashMap;
+use std::{env, fs, io};
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Error};
-use cargo_toml::{Dependency, DependencyDetail, DepsSet, Manifest};
-use clap::{Parser};
-use clap::ArgEnum;
-use git2::{Commit, Oid, Repository};
-use pathdiff::diff_paths;
-use regex::{CaptureMatches, Captures, Regex};
+use clap::{ArgEnum, Parser, Subcommand};
+use log::{info, Level, LevelFilter};
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
 use text_io::read;
+use workspace_gen::{
+    find_root, package_order, plan, release, release_order, restore_backups, scan, status, undo, write,
+    GenerateOptions, GitSuffix, Link, LinkState, Mode, Plan, Report, Restored, Scan, Section,
+    UrlScheme, VersionForm,
+};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
-    /// What mode to run the program in
+    /// What mode to run the program in, defaults to the one in .workspace-gen.toml
     #[clap(arg_enum, value_parser)]
-    mode: Mode,
-}
+    mode: Option<Mode>,
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
-enum Mode {
-    LocalPath,
-    GitRef,
-    Version,
-}
+    /// Directory containing the cargo projects, defaults to the current directory
+    #[clap(value_parser)]
+    path: Option<PathBuf>,
 
-fn main() -> Result<(), Error> {
-    let cli = Cli::parse();
+    /// Print the changes that would be made without writing anything
+    #[clap(short = 'n', long, value_parser, global = true)]
+    dry_run: bool,
 
-    // Create a new manifest
-    let mut uber = Manifest::from_str("[workspace]").context("Error creating manifest")?;
-    let mut packages = HashMap::new();
-    let mut tomls = HashMap::new();
-    let mut workspaces = Vec::new();
+    /// Keep the version next to local paths, so the crates can still be published
+    #[clap(long, value_parser, global = true)]
+    with_version: bool,
 
-    // Populate manifest by adding any manifest in subfolders
-    let path = env::current_dir()?;
-    build_manifest(&cli.mode, &path, &path, &mut uber, &mut tomls, &mut packages, &mut workspaces,
-                   None).context("Error building manifest")?;
-    if workspaces.len() == 0 {
-        return Err(anyhow!("No workspaces found. Are you in the correct directory?"));
-    }
-    if workspaces.len() == 1 {
-        return Err(anyhow!("Only one workspace found. Are you in the correct directory?"));
-    }
+    /// Drop the version next to local paths, even if .workspace-gen.toml says to keep it
+    #[clap(long, value_parser, global = true, overrides_with = "with-version")]
+    no_with_version: bool,
 
-    println!("{} files are about to be overwritten, would you like to continue? (Y/n)",
-             packages.len() + 1);
-    let line: String = read!("{}\n");
-    if !line.is_empty() && line.to_lowercase() != "y" {
-        println!("No files were changed.");
-        return Ok(());
-    }
+    /// Leave dependencies on unpushed commits alone instead of failing
+    #[clap(long, value_parser, global = true)]
+    allow_unpushed: bool,
 
-    // Rewrite manifests to refer to each other by relative path
-    update_manifests(&cli.mode, &tomls, &packages).context("Error updating manifests!")?;
-    rename_workspaces(&cli.mode, &workspaces).context("Error renaming workspace!")?;
+    /// Pin commits no remote contains to the preferred remote anyway, e.g. when about to push
+    #[clap(long, value_parser, global = true)]
+    assume_pushed: bool,
 
-    // Write out a new parent worksapce toml
-    let bytes = toml::ser::to_vec(&uber).context("Error serializing manifest")?;
-    let path = path.join("Cargo.toml");
-    fs::write(path, bytes).context("Error writing file")?;
+    /// Rewrite the URLs of git dependencies to https, e.g. for CI without SSH keys, or to ssh
+    #[clap(long, arg_enum, value_parser, default_value = "preserve", global = true)]
+    git_url_scheme: UrlScheme,
 
-    println!("Manifests have been updated!");
-    Ok(())
-}
+    /// Add or strip the .git at the end of git dependency URLs, so every clone writes the same
+    #[clap(long, arg_enum, value_parser, default_value = "keep", global = true)]
+    git_suffix: GitSuffix,
 
-fn rename_workspaces(mode: &Mode, workspaces: &Vec<PathBuf>) -> anyhow::Result<()> {
-    for workspace in workspaces {
-        let new_name = match mode {
-            Mode::LocalPath => "Cargo.bak.toml",
-            Mode::GitRef => "Cargo.toml",
-            Mode::Version => "Cargo.toml",
-        };
-        let new_name = workspace.parent().ok_or(anyhow!("Parent is required!"))?
-            .join(new_name);
-        fs::rename(workspace, new_name).context("Error renaming file!")?;
-    }
-    Ok(())
+    /// Pin repositories with uncommitted changes anyway, instead of failing
+    #[clap(long, value_parser, global = true)]
+    allow_dirty: bool,
+
+    /// Remote to prefer when pinning git refs, highest priority first (default: upstream, origin)
+    #[clap(long, value_parser, global = true)]
+    remote_priority: Vec<String>,
+
+    /// Pin git refs to this remote, skipping the priority search. HEAD must be on it unless
+    /// --assume-pushed is given.
+    #[clap(long, value_parser, global = true)]
+    remote: Option<String>,
+
+    /// How many threads to scan with (default: number of logical CPUs)
+    #[clap(short, long, value_parser, global = true)]
+    jobs: Option<usize>,
+
+    /// How to report what is going to change
+    #[clap(long, arg_enum, value_parser, default_value = "human", global = true)]
+    format: Format,
+
+    /// Copy manifests into .workspace-gen-backup before overwriting them, see the restore mode
+    #[clap(long, value_parser, global = true)]
+    backup: bool,
+
+    /// Registry to depend on published crates from in version mode, unless a dependency names one
+    #[clap(long, value_parser, global = true)]
+    registry: Option<String>,
+
+    /// Feature resolver for the generated workspace (default: the existing one, or 2)
+    #[clap(long, value_parser = ["1", "2"], global = true)]
+    resolver: Option<String>,
+
+    /// Link local packages even if they depend on each other in a cycle
+    #[clap(long, value_parser, global = true)]
+    allow_cycles: bool,
+
+    /// Move the [patch] tables of members into the root manifest, where cargo honors them
+    #[clap(long, value_parser, global = true)]
+    member_patches_to_root: bool,
+
+    /// Don't move child workspace manifests aside, for standalone builds. Their members are
+    /// still linked by path, but left out of the root workspace.
+    #[clap(long, value_parser, global = true)]
+    keep_child_workspaces: bool,
+
+    /// Say more about what is going on, twice to show every rewritten dependency
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only print errors, and whatever --dry-run or --format json asked for
+    #[clap(short, long, value_parser, conflicts_with = "verbose", global = true)]
+    quiet: bool,
+
+    /// Don't prompt for confirmation before overwriting files
+    #[clap(short, long, value_parser, global = true)]
+    yes: bool,
+
+    /// Walk up from the given directory to the one holding its repository, or an earlier run's
+    /// root, and scan from there
+    #[clap(long, value_parser, global = true)]
+    find_root: bool,
+
+    /// Show each manifest's diff and ask before rewriting it, instead of asking once
+    #[clap(short, long, value_parser, conflicts_with = "yes", global = true)]
+    interactive: bool,
+
+    /// Scan through symlinked directories and manifests, which are skipped by default
+    #[clap(long, value_parser, global = true)]
+    follow_symlinks: bool,
+
+    /// Only link this package, the others keep their specs and stay out of the workspace
+    #[clap(long, value_parser, global = true)]
+    only: Vec<String>,
+
+    /// Also link whatever local packages the --only ones depend on, directly or not
+    #[clap(long, value_parser, global = true)]
+    link_transitive: bool,
+
+    /// Leave this package out of linking, so it keeps its spec and stays out of the workspace
+    #[clap(long, value_parser, global = true)]
+    skip: Vec<String>,
+
+    /// Link the package in this directory when several share its name
+    #[clap(long, value_parser, global = true)]
+    prefer: Vec<PathBuf>,
+
+    /// Don't scan deeper than this, 0 looks only in the root's direct subdirectories
+    #[clap(long, value_parser, global = true)]
+    max_depth: Option<usize>,
+
+    /// Write local versions as this kind of requirement, instead of the bare version
+    #[clap(long, arg_enum, value_parser, global = true)]
+    version_req: Option<VersionForm>,
+
+    /// Copy the [profile.*] tables of this manifest into the root
+    #[clap(long, value_parser, global = true)]
+    profile_from: Option<PathBuf>,
+
+    /// Fail when a local version doesn't satisfy a dependent's version requirement
+    #[clap(long, value_parser, global = true)]
+    strict: bool,
+
+    /// Only rewrite these dependency tables, e.g. to keep dev-dependencies on releases
+    #[clap(long, arg_enum, value_parser, use_value_delimiter = true, global = true)]
+    sections: Vec<Section>,
+
+    /// Write the root manifest even if no packages are found, instead of failing
+    #[clap(long, value_parser, global = true)]
+    allow_empty: bool,
+
+    /// Turn on a feature wherever a dependency on a local crate is rewritten, as CRATE=FEATURE
+    #[clap(long = "feature", value_parser = parse_feature, global = true)]
+    features: Vec<(String, String)>,
+
+    /// Pin to short commit hashes, 12 characters unless given, more if that's ambiguous
+    #[clap(long, value_parser, min_values = 0, require_equals = true, default_missing_value = "12",
+        global = true)]
+    short_rev: Option<usize>,
+
+    /// Fetch each repository's remotes first, so commits pushed from elsewhere are found
+    #[clap(long, value_parser, global = true)]
+    fetch: bool,
+
+    /// Don't warn when a dependency is linked to a package outside the root, e.g. through a
+    /// symlink or --manifest-list
+    #[clap(long, value_parser, global = true)]
+    allow_external_paths: bool,
+
+    /// Link by absolute path in local-path modes, for crates on other drives. The manifests
+    /// only build on this machine.
+    #[clap(long, value_parser, global = true)]
+    absolute_paths: bool,
+
+    /// Also link the package at the scan root, adding the workspace to its manifest
+    #[clap(long, value_parser, global = true)]
+    include_root_package: bool,
+
+    /// Read the Cargo.toml paths to use, relative to the scan root, one per line, from this
+    /// file or - for stdin, instead of scanning the tree
+    #[clap(long, value_parser, global = true)]
+    manifest_list: Option<PathBuf>,
+
+    /// Keep members of an existing root even when their directory has no Cargo.toml anymore
+    #[clap(long, value_parser, global = true)]
+    no_prune: bool,
+
+    /// Leave out manifests that don't parse, e.g. templates, instead of failing
+    #[clap(long, value_parser, global = true)]
+    skip_invalid: bool,
+
+    /// Run cargo metadata on the result, and fail if cargo can't load the workspace
+    #[clap(long, value_parser, global = true)]
+    verify: bool,
+
+    /// File name to write the workspace manifest to, in the scan root
+    #[clap(long, value_parser, default_value = "Cargo.toml", global = true)]
+    root_manifest: String,
+
+    /// Merge into an existing root manifest even though workspace-gen didn't write it
+    #[clap(long, value_parser, global = true)]
+    force: bool,
+
+    /// Print the local packages with dependencies first, as release goes, and exit
+    #[clap(long, value_parser, global = true)]
+    print_order: bool,
+
+    /// Leave directories matching this glob, relative to the scan root, out of the workspace
+    #[clap(long, value_parser, global = true)]
+    exclude: Vec<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Directories to leave out of the scan, from .workspace-gen.toml
+    #[clap(skip)]
+    exclude_dirs: Vec<String>,
 }
 
-fn update_manifests(
-    mode: &Mode,
-    tomls: &HashMap<String, PathBuf>,
-    packages: &HashMap<String, PackageRef>,
-) -> anyhow::Result<()> {
-    let toml_paths: Vec<_> = tomls.values().collect();
-    for toml_path in toml_paths {
-        let input_str = fs::read_to_string(&toml_path).context("Error reading manifest")?;
-        let mut output_str = "".to_string();
-        let bytes = read(&toml_path).context("Error reading manifest")?;
-        let mani = Manifest::from_slice(&bytes).context("Error parsing manifest")?;
-        let pkg_path = toml_path.parent().context("Error getting parent path")?.to_path_buf();
-        let pkg_name = mani.package.unwrap().name;
-
-        let re = Regex::new(r"\n\[(.*)\]\n").context("Error creating regex")?;
-        let splitter = SplitCaptures::new(&re, input_str.as_str());
-        let mut cur_section = None;
-        for state in splitter {
-            match state {
-                SplitState::Unmatched(txt) => {
-                    if let Some(cur_section) = cur_section {
-                        let str = replace_deps(mode, packages, cur_section, &pkg_path, txt, &pkg_name)
-                            .context("Unable to replace dependencies!")?;
-                        output_str += str.as_str();
-                    } else {
-                        output_str += txt;
-                    }
-                    cur_section = None;
-                },
-                SplitState::Captured(caps) => {
-                    let section = &caps[1].to_string();
-                    output_str += format!("\n[{}]\n", section).as_str();
-                    cur_section = match section.as_str() {
-                        "dependencies" => Some(&mani.dependencies),
-                        "dev-dependencies" => Some(&mani.dev_dependencies),
-                        "build-dependencies" => Some(&mani.build_dependencies),
-                        _ => None
-                    };
-                },
-            }
+impl Cli {
+    fn options(&self, mode: Mode, path: PathBuf) -> GenerateOptions {
+        GenerateOptions {
+            mode,
+            path,
+            dry_run: self.dry_run,
+            with_version: self.with_version,
+            allow_unpushed: self.allow_unpushed,
+            assume_pushed: self.assume_pushed,
+            git_url_scheme: self.git_url_scheme,
+            git_suffix: self.git_suffix,
+            allow_dirty: self.allow_dirty,
+            remote_priority: self.remote_priority.clone(),
+            remote: self.remote.clone(),
+            backup: self.backup,
+            registry: self.registry.clone(),
+            resolver: self.resolver.clone(),
+            allow_cycles: self.allow_cycles,
+            member_patches_to_root: self.member_patches_to_root,
+            keep_child_workspaces: self.keep_child_workspaces,
+            follow_symlinks: self.follow_symlinks,
+            only: self.only.clone(),
+            link_transitive: self.link_transitive,
+            skip: self.skip.clone(),
+            prefer: self.prefer.clone(),
+            max_depth: self.max_depth,
+            version_req: self.version_req,
+            profile_from: self.profile_from.clone(),
+            strict: self.strict,
+            sections: self.sections.clone(),
+            allow_empty: self.allow_empty,
+            features: self.features.iter().fold(HashMap::new(), |mut acc, (name, feature)| {
+                acc.entry(name.clone()).or_insert_with(Vec::new).push(feature.clone());
+                acc
+            }),
+            short_rev: self.short_rev,
+            fetch: self.fetch,
+            allow_external_paths: self.allow_external_paths,
+            absolute_paths: self.absolute_paths,
+            include_root_package: self.include_root_package,
+            manifest_list: None,
+            prune: !self.no_prune,
+            skip_invalid: self.skip_invalid,
+            verify: self.verify,
+            root_manifest: self.root_manifest.clone(),
+            force: self.force,
+            exclude: self.exclude.clone(),
+            exclude_dirs: self.exclude_dirs.clone(),
         }
-
-        fs::write(&toml_path, output_str)?;
     }
-    Ok(())
 }
 
-fn replace_deps(
-    mode: &Mode,
-    packages: &HashMap<String, PackageRef>,
-    deps: &DepsSet,
-    pkg_path: &PathBuf,
-    input_str: &str,
-    pkg_name: &String,
-) -> anyhow::Result<String> {
-    let mut str = input_str.to_string();
-    for (name, src_dep) in deps {
-        let other_pkg = match packages.get(&name.clone()) {
-            None => continue,
-            Some(it) => it,
-        };
-        let this_pkg = &packages[pkg_name];
-        let relative = diff_paths(&other_pkg.path, pkg_path).ok_or(anyhow!("Can't diff paths!"))?;
-        let relative = relative.to_str().ok_or(anyhow!("Can't diff paths!"))?.to_string();
-        let new_dep = match mode {
-            Mode::LocalPath => clone_path_dep(src_dep, relative),
-            Mode::GitRef => {
-                if this_pkg.git.url == other_pkg.git.url {
-                    clone_path_dep(src_dep, relative)
-                } else {
-                    clone_git_dep(src_dep, &other_pkg.git)
-                }
-            }
-            Mode::Version => {
-                if this_pkg.git.url == other_pkg.git.url {
-                    clone_path_dep(src_dep, relative)
-                } else {
-                    clone_ver_dep(src_dep, &other_pkg.version)
-                }
-            },
-        };
-        let new_dep = dep_to_string(&new_dep).context("Error serializing manifest")?;
-        let new_dep = format!("{} = {}", name, new_dep);
-        let re = Regex::new(format!(r#"{}\s*=\s*.*"#, name).as_ref())
-            .context("Error creating regex")?;
-        str = re.replace_all(&str, new_dep).to_string();
-    }
-    return Ok(str);
+const PLAN_FILE: &str = "workspace-gen-plan.json";
+
+#[derive(Subcommand)]
+enum Command {
+    /// Work out what a mode would change and save it, without touching any manifest
+    Plan {
+        #[clap(arg_enum, value_parser)]
+        mode: Mode,
+
+        /// Directory containing the cargo projects, defaults to the current directory
+        #[clap(value_parser)]
+        path: Option<PathBuf>,
+
+        /// Where to save the plan
+        #[clap(short, long, value_parser, default_value = PLAN_FILE)]
+        output: PathBuf,
+    },
+    /// Fail, showing the diffs, if a mode would change anything, e.g. as a CI check
+    Check {
+        #[clap(arg_enum, value_parser)]
+        mode: Mode,
+
+        /// Directory containing the cargo projects, defaults to the current directory
+        #[clap(value_parser)]
+        path: Option<PathBuf>,
+    },
+    /// Carry out a saved plan, exactly as it was reviewed
+    Apply {
+        #[clap(value_parser, default_value = PLAN_FILE)]
+        plan: PathBuf,
+    },
 }
 
-fn dep_to_string(dep: &Dependency) -> anyhow::Result<String> {
-    let det = match dep {
-        Dependency::Simple(_) => Err(anyhow!("Can't serialize simple dependencies!"))?,
-        Dependency::Detailed(det) => det,
-    };
-    let mut map = HashMap::<String, String>::new();
-    let put = |map: &mut HashMap<String, String>, k: &str, v: &Option<String>| {
-        let _ = match v {
-            None => None,
-            Some(v) => map.insert(k.to_string(), format!("\"{}\"", v))
-        };
-    };
+const CONFIG_FILE: &str = ".workspace-gen.toml";
+
+/// Defaults read from .workspace-gen.toml in the scan root, flags take precedence
+#[derive(Default, Deserialize)]
+struct Config {
+    mode: Option<Mode>,
+    #[serde(default)]
+    remote_priority: Vec<String>,
+    #[serde(default)]
+    exclude_dirs: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    with_version: Option<bool>,
+}
 
-    // Type of dep
-    put(&mut map, "path", &det.path);
-    put(&mut map, "version", &det.version);
-    put(&mut map, "git", &det.git);
-    if map.is_empty() {
-        Err(anyhow!("Need one of: path, version, git"))?;
+impl Config {
+    fn load(base: &Path) -> anyhow::Result<Config> {
+        let path = base.join(CONFIG_FILE);
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let str = fs::read_to_string(&path).context("Error reading config")?;
+        toml::from_str(&str).with_context(|| format!("Error parsing {:?}", path))
     }
 
-    // git specific links
-    if map.contains_key("git") {
-        if map.contains_key("path") {
-            Err(anyhow!("Values are mutually exclusive: path, git"))?;
+    fn apply(self, cli: &mut Cli) {
+        cli.mode = cli.mode.or(self.mode);
+        if cli.remote_priority.is_empty() {
+            cli.remote_priority = self.remote_priority;
         }
-        if map.contains_key("version") {
-            Err(anyhow!("Values are mutually exclusive: version, git"))?;
+        cli.exclude_dirs = self.exclude_dirs;
+        cli.exclude.extend(self.exclude);
+        if !cli.with_version && !cli.no_with_version {
+            cli.with_version = self.with_version.unwrap_or(false);
         }
-        let mut git = HashMap::<String, String>::new();
-        put(&mut git, "branch", &det.branch);
-        put(&mut git, "tag", &det.tag);
-        put(&mut git, "rev", &det.rev);
-        if git.is_empty() {
-            Err(anyhow!("Need one of: branch, tag, rev"))?;
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ArgEnum)]
+enum Format {
+    Human,
+    Json,
+    /// Just the changed dependency lines, under their tables
+    TomlPatch,
+}
+
+fn main() -> Result<(), Error> {
+    let mut cli = Cli::parse();
+    init_logging(if cli.quiet { None } else { Some(cli.verbose) });
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.jobs.unwrap_or(0))
+        .build_global()
+        .context("Error starting worker threads")?;
+
+    let mut output = None;
+    let mut check = false;
+    match cli.command.take() {
+        Some(Command::Plan { mode, path, output: file }) => {
+            cli.mode = Some(mode);
+            cli.path = path;
+            output = Some(file);
         }
-        if git.len() > 1 {
-            Err(anyhow!("Values are mutually exclusive: branch, tag, rev"))?;
+        Some(Command::Check { mode, path }) => {
+            cli.mode = Some(mode);
+            cli.path = path;
+            cli.dry_run = true;
+            check = true;
         }
-        git.iter().for_each(|(k, v)| {
-            let _ = map.insert(k.clone(), v.clone());
-        });
+        Some(Command::Apply { plan: file }) => {
+            let plan = Plan::load(&file)?;
+            let opts = cli.options(plan.mode, plan.path.clone());
+            return run(&cli, &opts, plan);
+        }
+        None => {}
     }
 
-    match det.default_features {
-        Some(false) => {
-            map.insert("default-features".to_string(), "false".to_string());
-        },
-        _ => {}
+    let path = match &cli.path {
+        None => env::current_dir().context("Error getting current directory")?,
+        Some(path) => fs::canonicalize(path)
+            .with_context(|| format!("Error finding directory {:?}", path))?,
+    };
+    let path = match cli.find_root {
+        true => {
+            let root = find_root(&path);
+            info!("Scanning from {:?}", root);
+            root
+        }
+        false => path,
+    };
+    Config::load(&path)?.apply(&mut cli);
+    let mode = cli.mode.ok_or(anyhow!("No mode given, pass one or set it in {}", CONFIG_FILE))?;
+    if mode == Mode::Restore {
+        let restored = restore_backups(&path, cli.dry_run).context("Error restoring backups!")?;
+        print_restored(&restored, cli.dry_run, cli.quiet);
+        return Ok(());
     }
-    if det.optional {
-        map.insert("optional".to_string(), "true".to_string());
+    if mode == Mode::Undo {
+        let restored = undo(&path, cli.dry_run).context("Error undoing last run!")?;
+        print_restored(&restored, cli.dry_run, cli.quiet);
+        return Ok(());
     }
-    if !det.features.is_empty() {
-        let features: Vec<_> = det.features.iter().map(|it| format!("\"{}\"", it)).collect();
-        let features = features.join(", ");
-        let features = format!("[{}]", features);
-        map.insert("features".to_string(), features);
+    let mut opts = cli.options(mode, path);
+    if let Some(file) = &cli.manifest_list {
+        opts.manifest_list = Some(read_manifest_list(file)?);
     }
-
-    // short-hand for version
-    if map.len() == 1 {
-        if let Some(ver) = map.get("version") {
-            return Ok(ver.clone());
+    if cli.print_order {
+        let Scan { packages, graph, .. } = scan(&opts)?;
+        for name in package_order(&packages, &graph)? {
+            println!("{}", name);
         }
+        return Ok(());
     }
-
-    let field_order = [
-        "path",
-        "git",
-        "branch",
-        "tag",
-        "rev",
-        "version",
-        "features",
-        "default-features",
-        "optional",
-    ];
-
-    // everything else
-    let mut terms = vec![];
-    for key in field_order.iter() {
-        let val = match map.get(&key.to_string()) {
-            Some(val) => val.clone(),
-            None => continue,
-        };
-        let text = format!("{} = {}", key, val);
-        terms.push(text);
+    if mode == Mode::Release {
+        return run_release(&cli, &opts);
     }
-    let res = format!("{{ {} }}", terms.join(", "));
-    Ok(res)
-}
-
-fn clone_path_dep(src_dep: &Dependency, relative: String) -> Dependency {
-    match src_dep {
-        Dependency::Simple(_) => {
-            Dependency::Detailed {
-                0: DependencyDetail {
-                    version: None,
-                    registry: None,
-                    registry_index: None,
-                    path: Some(relative),
-                    git: None,
-                    branch: None,
-                    tag: None,
-                    rev: None,
-                    features: vec![],
-                    optional: false,
-                    default_features: None,
-                    package: None
-                }
-            }
+    if mode == Mode::Status {
+        let links = status(&opts)?;
+        if cli.format == Format::Json {
+            return print_json(&links);
         }
-        Dependency::Detailed(it) => {
-            Dependency::Detailed {
-                0: DependencyDetail {
-                    version: it.version.clone(),
-                    registry: None,
-                    registry_index: None,
-                    path: Some(relative),
-                    git: None,
-                    branch: None,
-                    tag: None,
-                    rev: None,
-                    features: it.features.clone(),
-                    optional: it.optional,
-                    default_features: it.default_features,
-                    package: None
-                }
-            }
+        print_links(&links, &opts.path);
+        return Ok(());
+    }
+    let plan = plan(&opts)?;
+    if let Some(file) = output {
+        plan.save(&file)?;
+        if !cli.quiet {
+            println!("Planned changes to {} manifests, saved to {:?}. Run apply to carry them out.",
+                     plan.report().written.len(), file);
         }
+        return Ok(());
     }
-}
-
-fn clone_ver_dep(src_dep: &Dependency, version: &String) -> Dependency {
-    match src_dep {
-        Dependency::Simple(_) => {
-            Dependency::Detailed {
-                0: DependencyDetail {
-                    version: Some(version.clone()),
-                    registry: None,
-                    registry_index: None,
-                    path: None,
-                    git: None,
-                    branch: None,
-                    tag: None,
-                    rev: None,
-                    features: vec![],
-                    optional: false,
-                    default_features: None,
-                    package: None
-                }
+    if check {
+        let up_to_date = plan.is_up_to_date();
+        if up_to_date && cli.format == Format::Human {
+            if !cli.quiet {
+                println!("Manifests are up to date.");
             }
+            return Ok(());
         }
-        Dependency::Detailed(it) => {
-            Dependency::Detailed {
-                0: DependencyDetail {
-                    version: Some(version.clone()),
-                    registry: None,
-                    registry_index: None,
-                    path: None,
-                    git: None,
-                    branch: None,
-                    tag: None,
-                    rev: None,
-                    features: it.features.clone(),
-                    optional: it.optional,
-                    default_features: it.default_features,
-                    package: None
-                }
-            }
+        run(&cli, &opts, plan)?;
+        if !up_to_date {
+            return Err(anyhow!("Manifests are out of date, run the same mode without check to fix"));
         }
+        return Ok(());
     }
+    run(&cli, &opts, plan)
 }
 
-fn clone_git_dep(src_dep: &Dependency, git_ref: &GitRef) -> Dependency {
-    match src_dep {
-        Dependency::Simple(_) => {
-            Dependency::Detailed {
-                0: DependencyDetail {
-                    version: None,
-                    registry: None,
-                    registry_index: None,
-                    path: None,
-                    git: Some(git_ref.url.clone()),
-                    branch: None,
-                    tag: None,
-                    rev: Some(git_ref.oid.to_string()),
-                    features: vec![],
-                    optional: false,
-                    default_features: None,
-                    package: None
-                }
-            }
+/// Shows the order repositories go out in, and releases them once confirmed
+fn run_release(cli: &Cli, opts: &GenerateOptions) -> Result<(), Error> {
+    // nothing to patch, so toml-patch reads like human
+    let human = cli.format != Format::Json;
+    if !human && !cli.dry_run && !cli.yes {
+        return Err(anyhow!("Can't prompt for confirmation with --format json. Pass --yes or --dry-run."));
+    }
+    let order = release_order(opts)?;
+    if cli.dry_run && !human {
+        return print_json(&order);
+    }
+    if human && (cli.dry_run || !cli.quiet) {
+        for (i, step) in order.iter().enumerate() {
+            println!("{}. {} -> {}", i + 1, step.root.display(), step.remote);
         }
-        Dependency::Detailed(it) => {
-            Dependency::Detailed {
-                0: DependencyDetail {
-                    version: None,
-                    registry: None,
-                    registry_index: None,
-                    path: None,
-                    git: Some(git_ref.url.clone()),
-                    branch: None,
-                    tag: None,
-                    rev: Some(git_ref.oid.to_string()),
-                    features: it.features.clone(),
-                    optional: it.optional,
-                    default_features: it.default_features,
-                    package: None
-                }
-            }
+    }
+    if cli.dry_run {
+        println!("Dry run, nothing was committed or pushed.");
+        return Ok(());
+    }
+    let question = format!("{} repositories are about to be committed to and pushed", order.len());
+    if !cli.yes && !confirm(&question)? {
+        println!("Nothing was committed or pushed.");
+        return Ok(());
+    }
+    let steps = release(opts)?;
+    if !human {
+        return print_json(&steps);
+    }
+    if !cli.quiet {
+        for step in &steps {
+            let pushed = match step.pushed {
+                true => format!(", pushed to {}", step.remote),
+                false => "".to_string(),
+            };
+            println!("{}: committed {} manifests{}", step.root.display(), step.committed.len(), pushed);
         }
     }
+    Ok(())
 }
 
-#[derive(Debug, Clone)]
-struct GitRef {
-    pub url: String,
-    pub oid: Oid,
-}
-
-struct PackageRef {
-    pub path: PathBuf,
-    pub git: GitRef,
-    pub version: String,
-}
+/// Shows, confirms and writes a plan
+fn run(cli: &Cli, opts: &GenerateOptions, mut plan: Plan) -> Result<(), Error> {
 
-fn contains_commit(
-    search: &Commit,
-    target: &Commit,
-) -> bool {
-    if search.id() == target.id() {
-        return true;
+    let human = cli.format == Format::Human;
+    if !human && !cli.dry_run && !cli.yes {
+        return Err(anyhow!("Can't prompt for confirmation with --format json or toml-patch. Pass --yes \
+                            or --dry-run."));
     }
-    for parent in search.parents() {
-        if contains_commit(&parent, target) {
-            return true;
-        }
+    if cli.dry_run && cli.format == Format::TomlPatch {
+        print_patch(&plan);
+        return Ok(());
     }
-    false
-}
-
-fn build_manifest(
-    mode: &Mode,
-    base: &PathBuf,
-    path: &PathBuf,
-    uber: &mut Manifest,
-    tomls: &mut HashMap<String, PathBuf>,
-    packages: &mut HashMap<String, PackageRef>,
-    workspaces: &mut Vec<PathBuf>,
-    mut git_ref: Option<GitRef>,
-) -> anyhow::Result<()> {
-    if let Ok(repo) = Repository::open(&path) {
-        let head = repo.head().context("Error getting HEAD!")?
-            .peel_to_commit().context("Error getting commit!")?;
-        let remote = best_remote_with_commit(&repo, &head)?;
-        git_ref = Some(GitRef { url: remote, oid: head.id() });
-    }
-
-    // scan subfolders
-    let paths = path.read_dir().context("Error scanning directory")?;
-    for path in paths {
-        let path = path.context("Error enumerating files")?;
-        if path.metadata().context("Error getting file metadata")?.is_dir() {
-            build_manifest(mode, base, &path.path(), uber, tomls, packages, workspaces, git_ref.clone())
-                .context("Error building manifest")?;
-            continue;
-        }
-        let name = path.file_name();
-        if name != "Cargo.toml" && name != "Cargo.bak.toml" {
-            continue;
+    if cli.dry_run && !human {
+        return print_json(&plan.report());
+    }
+    if cli.dry_run {
+        for edit in &plan.edits {
+            print_diff(&edit.path, &edit.before, &edit.after);
         }
-        let bytes = read(path.path()).context("Error reading bytes")?;
-        let abs = path.path().parent().ok_or(anyhow!("Error getting parent path"))?.to_path_buf();
-        let relative = diff_paths(&abs, &base).ok_or(anyhow!("Error relativizing path"))?;
-        if relative.parent().is_none() {
-            continue; // top level relative path
+        print_diff(&plan.root, plan.root_before.as_deref().unwrap_or_default(), &plan.root_after);
+        for (from, to) in &plan.renames {
+            println!("Would rename {:?} to {:?}", from, to);
         }
-        let relative = relative.to_str().ok_or(anyhow!("Error getting path"))?.to_string();
-        let mani = Manifest::from_slice(&bytes).context("Error reading manifest")?;
-        if let Some(pkg) = mani.package.as_ref() {
-            println!("{} is at {:?}", pkg.name, git_ref);
-            let git_ref = match &git_ref {
-                None => Err(anyhow!("No git repo found!"))?,
-                Some(it) => it,
-            };
-            let pkg = mani.package.ok_or(anyhow!("No package found!"))?;
-            let pkg_ref = PackageRef {
-                path: abs,
-                git: git_ref.clone(),
-                version: pkg.version,
-            };
+        println!("Dry run, no files were changed.");
+        return Ok(());
+    }
 
-            packages.insert(pkg.name.clone(), pkg_ref);
-            tomls.insert(pkg.name.clone(), path.path().clone());
-            uber.workspace.as_mut().ok_or(anyhow!("workspace needed!"))?
-                .members.push(relative.clone());
-        }
-        if let Some(mani) = mani.workspace.as_ref() {
-            uber.workspace.as_mut().ok_or(anyhow!("workspace needed!"))?
-                .exclude.push(relative.clone());
-            for exclude in &mani.exclude {
-                uber.workspace.as_mut().ok_or(anyhow!("workspace needed!"))?
-                    .exclude.push(format!("{}/{}", relative.to_string(), exclude));
+    if cli.interactive {
+        for i in 0..plan.edits.len() {
+            let edit = &plan.edits[i];
+            if edit.before == edit.after {
+                continue;
+            }
+            print_diff(&edit.path, &edit.before, &edit.after);
+            let path = edit.path.clone();
+            if !confirm(&format!("Rewrite {:?}", path))? {
+                plan.skip(&path);
             }
-            workspaces.push(path.path());
         }
     }
+    let question = format!("Manifests are about to change: {}", describe_changes(&plan));
+    if !cli.yes && !cli.interactive && !confirm(&question)? {
+        println!("No files were changed.");
+        return Ok(());
+    }
+
+    write(opts, &plan)?;
+    let report = plan.report();
+
+    if cli.format == Format::TomlPatch {
+        print_patch(&plan);
+        return Ok(());
+    }
+    if !human {
+        return print_json(&report);
+    }
+    if !cli.quiet {
+        println!("Manifests have been updated!");
+        print_report(&report, &plan.path, cli.verbose);
+    }
     Ok(())
 }
 
-fn best_remote_with_commit(
-    repo: &Repository,
-    head: &Commit
-) -> anyhow::Result<String> {
-    let order = vec!["upstream", "origin"];
-    let all_remotes = get_remotes(&repo)?;
-    let mut best_remote = None;
-    let mut best_score = usize::max_value();
-    for reference in repo.references().context("Error getting references!")? {
-        let reference = reference.context("Error getting reference!")?;
-        if !reference.is_remote() {
-            continue;
-        }
-        let name = reference.name().ok_or(anyhow!("Error getting reference name!"))?;
-        let parts: Vec<_> = name.split("/").collect();
-        if parts.len() < 3 || parts[0] != "refs" || parts[1] != "remotes" {
-            Err(anyhow!("Invalid reference name!"))?;
-        }
-        let remote = parts[2];
-        let score = order.iter().position(|it| it == &remote).unwrap_or(usize::max_value() - 1);
-        if score >= best_score {
-            continue;
-        }
-        let commit = reference.peel_to_commit().context("Error getting commit!")?;
-        if !contains_commit(&commit, &head) {
-            continue;
-        }
-        best_remote = Some(all_remotes[remote].clone());
-        best_score = score;
+/// e.g. "3 modified, 1 created, 2 deleted", moved manifests count as created and deleted
+fn describe_changes(plan: &Plan) -> String {
+    let mut modified = plan.edits.iter().filter(|it| it.before != it.after).count();
+    let mut created = plan.renames.len();
+    match &plan.root_before {
+        None => created += 1,
+        Some(_) if plan.root_changed() => modified += 1,
+        Some(_) => {},
+    }
+    format!("{} modified, {} created, {} deleted", modified, created, plan.renames.len())
+}
+
+fn parse_feature(arg: &str) -> anyhow::Result<(String, String)> {
+    let (name, feature) = arg.split_once('=').ok_or(anyhow!("Expected CRATE=FEATURE"))?;
+    Ok((name.to_string(), feature.to_string()))
+}
+
+/// Reads newline separated manifest paths, relative to the scan root
+fn read_manifest_list(file: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut str = String::new();
+    if file == Path::new("-") {
+        io::stdin().read_to_string(&mut str).context("Error reading manifest list from stdin")?;
+    } else {
+        str = fs::read_to_string(file)
+            .with_context(|| format!("Error reading manifest list {:?}", file))?;
     }
-    let path = repo.path().to_str().ok_or(anyhow!("Can't get repo path!"))?;
-    Ok(best_remote.ok_or(anyhow!(
-        "No remote found for {}. Do you have committed work that is not pushed?", path
-    ))?)
+    let lines = str.lines().map(|it| it.trim()).filter(|it| !it.is_empty());
+    Ok(lines.map(PathBuf::from).collect())
 }
 
-fn get_remotes(repo: &Repository) -> anyhow::Result<HashMap<String, String>> {
-    let mut remotes = HashMap::<String, String>::new();
-    for remote in &repo.remotes().context("Error getting remotes!")? {
-        let remote = remote.ok_or(anyhow!("Unable to get remote!"))?;
-        let remote = repo.find_remote(&remote).context("Unable to find remote!")?;
-        let url = remote.url().ok_or(anyhow!("Unable to get URL!"))?;
-        let name = remote.name().ok_or(anyhow!("Unable to get name!"))?;
-        remotes.insert(name.to_string(), url.to_string());
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!("Refusing to continue without confirmation. Pass --yes to continue."));
     }
-    Ok(remotes)
+    println!("{}, would you like to continue? (Y/n)", question);
+    let line: String = read!("{}\n");
+    Ok(line.is_empty() || line.to_lowercase() == "y")
 }
 
-struct SplitCaptures<'r, 't> {
-    finder: CaptureMatches<'r, 't>,
-    text: &'t str,
-    last: usize,
-    caps: Option<Captures<'t>>,
+/// Warnings show unless `--quiet`, `-v` adds what was found, `-vv` every rewritten dependency.
+/// RUST_LOG still overrides this.
+fn init_logging(verbose: Option<u8>) {
+    let level = match verbose {
+        None => LevelFilter::Error,
+        Some(0) => LevelFilter::Warn,
+        Some(1) => LevelFilter::Info,
+        Some(_) => LevelFilter::Debug,
+    };
+    env_logger::Builder::new()
+        .filter_level(level.min(LevelFilter::Warn))
+        .filter_module(module_path!(), level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            Level::Error => writeln!(buf, "Error: {}", record.args()),
+            Level::Warn => writeln!(buf, "Warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
 }
 
-impl<'r, 't> SplitCaptures<'r, 't> {
-    fn new(re: &'r Regex, text: &'t str) -> SplitCaptures<'r, 't> {
-        SplitCaptures {
-            finder: re.captures_iter(text),
-            text,
-            last: 0,
-            caps: None,
+/// Counts by default, `-v` lists every file as well
+fn print_report(report: &Report, base: &Path, verbose: u8) {
+    let relative = |path: &PathBuf| path.strip_prefix(base).unwrap_or(path).display().to_string();
+    println!("Found {} packages and rewrote {} dependencies", report.packages.len(), report.edges.len());
+    println!("Wrote {} manifests, {} were already up to date",
+             report.written.len(), report.unchanged.len());
+    if verbose > 0 {
+        for path in &report.written {
+            println!("  wrote {}", relative(path));
         }
     }
+    if !report.skipped.is_empty() {
+        let skipped: Vec<_> = report.skipped.iter().map(relative).collect();
+        println!("Skipped {}", skipped.join(", "));
+    }
+    if !report.invalid.is_empty() {
+        let invalid: Vec<_> = report.invalid.iter().map(relative).collect();
+        println!("Left out manifests that don't parse: {}", invalid.join(", "));
+    }
+    if !report.pruned.is_empty() {
+        println!("Pruned members that no longer exist: {}", report.pruned.join(", "));
+    }
+    if !report.kept.is_empty() {
+        let kept: Vec<_> = report.kept.iter().map(relative).collect();
+        println!("Kept {}", kept.join(", "));
+    }
+    if !report.deleted.is_empty() {
+        let deleted: Vec<_> = report.deleted.iter().map(relative).collect();
+        println!("Moved {} out of the way", deleted.join(", "));
+    }
 }
 
-#[derive(Debug)]
-enum SplitState<'t> {
-    Unmatched(&'t str),
-    Captured(Captures<'t>),
+fn print_restored(restored: &[Restored], dry_run: bool, quiet: bool) {
+    if quiet && !dry_run {
+        return;
+    }
+    for it in restored {
+        let verb = match (dry_run, it.removed) {
+            (true, false) => "Would restore",
+            (true, true) => "Would remove",
+            (false, false) => "Restored",
+            (false, true) => "Removed",
+        };
+        println!("{} {:?}", verb, it.path);
+    }
 }
 
-impl<'r, 't> Iterator for SplitCaptures<'r, 't> {
-    type Item = SplitState<'t>;
-
-    fn next(&mut self) -> Option<SplitState<'t>> {
-        if let Some(caps) = self.caps.take() {
-            return Some(SplitState::Captured(caps));
+fn print_links(links: &[Link], base: &Path) {
+    let rows: Vec<_> = links.iter()
+        .map(|it| {
+            let state = match it.state {
+                LinkState::LocalPath => "local-path",
+                LinkState::GitRef => "git-ref",
+                LinkState::Version => "version",
+                LinkState::Inherited => "inherited",
+                LinkState::External => "external",
+            };
+            let path = it.path.strip_prefix(base).unwrap_or(&it.path).display().to_string();
+            [path, it.name.clone(), state.to_string(), it.spec.clone()]
+        })
+        .collect();
+    let header = ["MANIFEST", "DEPENDENCY", "STATE", "SPEC"].map(String::from);
+    let mut widths = [0; 3];
+    for row in rows.iter().chain([&header]) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
         }
-        match self.finder.next() {
-            None => {
-                if self.last >= self.text.len() {
-                    None
-                } else {
-                    let s = &self.text[self.last..];
-                    self.last = self.text.len();
-                    Some(SplitState::Unmatched(s))
-                }
-            }
-            Some(caps) => {
-                let m = caps.get(0).unwrap();
-                let unmatched = &self.text[self.last..m.start()];
-                self.last = m.end();
-                self.caps = Some(caps);
-                Some(SplitState::Unmatched(unmatched))
+    }
+    for row in [&header].into_iter().chain(&rows) {
+        println!("{:w0$}  {:w1$}  {:w2$}  {}", row[0], row[1], row[2], row[3],
+                 w0 = widths[0], w1 = widths[1], w2 = widths[2]);
+    }
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(value).context("Error serializing report")?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// The rewritten dependency lines of each manifest as -/+ pairs, lighter than a full diff
+fn print_patch(plan: &Plan) {
+    for edit in plan.edits.iter().filter(|it| !it.deps.is_empty()) {
+        let path = edit.path.strip_prefix(&plan.path).unwrap_or(&edit.path);
+        println!("--- {}", path.display());
+        let mut section = None;
+        for dep in &edit.deps {
+            if section != Some(&dep.section) && !dep.section.is_empty() {
+                println!(" [{}]", dep.section);
             }
+            section = Some(&dep.section);
+            // a spec spread over several lines keeps its marker on each
+            println!("-{} = {}", dep.name, dep.from.replace('\n', "\n-"));
+            println!("+{} = {}", dep.name, dep.to.replace('\n', "\n+"));
         }
     }
 }
+
+fn print_diff(path: &Path, before: &str, after: &str) {
+    if before == after {
+        return;
+    }
+    let name = path.to_string_lossy();
+    let diff = TextDiff::from_lines(before, after);
+    print!("{}", diff.unified_diff().header(&name, &name));
+}
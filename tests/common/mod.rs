@@ -0,0 +1,165 @@
+//! Throwaway directories of git repositories to run the modes against
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use git2::{IndexAddOption, Repository, Signature};
+use tempfile::TempDir;
+use workspace_gen::{plan, write, GenerateOptions, Mode, Plan};
+
+pub struct Fixture {
+    dir: TempDir,
+    path: PathBuf,
+}
+
+impl Fixture {
+    pub fn new() -> Fixture {
+        let dir = tempfile::tempdir().expect("Error creating temp dir");
+        let path = fs::canonicalize(dir.path()).expect("Error resolving temp dir");
+        Fixture { dir, path }
+    }
+
+    /// Repos a (a-core, a-util) and b (b-core, b-util), each a workspace, where b depends on a
+    /// directly and through its `[workspace.dependencies]`
+    pub fn two_repos() -> Fixture {
+        let fix = Fixture::new();
+        fix.repo("a", &[
+            ("Cargo.toml", "[workspace]\nmembers = [\"a-core\", \"a-util\"]\n"),
+            ("a-core/Cargo.toml", &package("a-core", "0.1.0", "a-util = { path = \"../a-util\" }\n")),
+            ("a-core/src/lib.rs", ""),
+            ("a-util/Cargo.toml", &package("a-util", "0.1.0", "")),
+            ("a-util/src/lib.rs", ""),
+        ]);
+        fix.repo("b", &[
+            ("Cargo.toml", "[workspace]\nmembers = [\"b-core\", \"b-util\"]\n\n\
+                            [workspace.dependencies]\na-core = \"0.1.0\"\n\
+                            b-util = { path = \"b-util\" }\n"),
+            ("b-core/Cargo.toml", &format!("{}\n[dev-dependencies]\na-util = \"0.1.0\"\n",
+                                           package("b-core", "0.2.0", "a-core = { workspace = true }\n\
+                                                   b-util = { workspace = true }\n"))),
+            ("b-core/src/lib.rs", ""),
+            ("b-util/Cargo.toml", &package("b-util", "0.2.0", "a-util = \"0.1.0\"\n")),
+            ("b-util/src/lib.rs", ""),
+        ]);
+        fix
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Initializes a repository at `name` and commits `files` to it
+    pub fn repo(&self, name: &str, files: &[(&str, &str)]) -> &Fixture {
+        let root = self.path.join(name);
+        let repo = Repository::init(&root).expect("Error creating repo");
+        for (file, contents) in files {
+            self.write(&format!("{}/{}", name, file), contents);
+        }
+        commit_all(&repo, "initial");
+        self
+    }
+
+    pub fn write(&self, file: &str, contents: &str) {
+        let path = self.path.join(file);
+        fs::create_dir_all(path.parent().unwrap()).expect("Error creating dir");
+        fs::write(path, contents).expect("Error writing file");
+    }
+
+    pub fn read(&self, file: &str) -> String {
+        fs::read_to_string(self.path.join(file)).unwrap_or_else(|_| panic!("Error reading {}", file))
+    }
+
+    pub fn exists(&self, file: &str) -> bool {
+        self.path.join(file).exists()
+    }
+
+    pub fn opts(&self, mode: Mode) -> GenerateOptions {
+        GenerateOptions::new(mode, self.path.clone())
+    }
+
+    /// Plans and writes a mode, like `workspace-gen <mode> -y`
+    pub fn run(&self, mode: Mode) -> Plan {
+        self.run_with(&self.opts(mode))
+    }
+
+    pub fn run_with(&self, opts: &GenerateOptions) -> Plan {
+        let plan = plan(opts).expect("Error planning");
+        write(opts, &plan).expect("Error writing");
+        plan
+    }
+
+    /// Every manifest under the root, by relative path, to compare whole trees
+    pub fn manifests(&self) -> Vec<(String, String)> {
+        let mut found = vec![];
+        collect_manifests(&self.path, &self.path, &mut found);
+        found.sort();
+        found
+    }
+
+    /// Has cargo load the workspace a manifest is in, without resolving anything
+    pub fn cargo_metadata(&self, file: &str) -> Result<(), String> {
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+        let out = Command::new(cargo)
+            .args(["metadata", "--no-deps", "--offline", "--format-version", "1", "--manifest-path"])
+            .arg(self.path.join(file))
+            .output()
+            .expect("Error running cargo");
+        match out.status.success() {
+            true => Ok(()),
+            false => Err(String::from_utf8_lossy(&out.stderr).to_string()),
+        }
+    }
+
+    /// Adds a remote to repo `name` whose `main` branch is at HEAD, as if it had been pushed
+    pub fn remote(&self, name: &str, remote: &str, url: &str) {
+        let repo = Repository::open(self.path.join(name)).expect("Error opening repo");
+        repo.remote(remote, url).expect("Error adding remote");
+        self.remote_ref(name, remote);
+    }
+
+    /// Points `refs/remotes/<remote>/main` at HEAD, whether or not the remote exists
+    pub fn remote_ref(&self, name: &str, remote: &str) {
+        let repo = Repository::open(self.path.join(name)).expect("Error opening repo");
+        let head = repo.head().unwrap().target().unwrap();
+        repo.reference(&format!("refs/remotes/{}/main", remote), head, true, "test")
+            .expect("Error creating reference");
+    }
+
+    pub fn commit(&self, name: &str, message: &str) {
+        let repo = Repository::open(self.path.join(name)).expect("Error opening repo");
+        commit_all(&repo, message);
+    }
+}
+
+/// A package manifest with these `[dependencies]` lines
+pub fn package(name: &str, version: &str, deps: &str) -> String {
+    format!("[package]\nname = \"{}\"\nversion = \"{}\"\nedition = \"2021\"\n\n[dependencies]\n{}",
+            name, version, deps)
+}
+
+fn commit_all(repo: &Repository, message: &str) {
+    let mut index = repo.index().expect("Error getting index");
+    index.add_all(["*"], IndexAddOption::DEFAULT, None).expect("Error adding files");
+    index.write().expect("Error writing index");
+    let tree = repo.find_tree(index.write_tree().expect("Error writing tree")).unwrap();
+    let sig = Signature::now("test", "test@example.com").unwrap();
+    let parent = repo.head().ok().and_then(|it| it.peel_to_commit().ok());
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents).expect("Error committing");
+}
+
+fn collect_manifests(base: &Path, dir: &Path, found: &mut Vec<(String, String)>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if path.is_dir() && name != ".git" && name != "target" {
+            collect_manifests(base, &path, found);
+        } else if name.ends_with(".toml") {
+            let relative = path.strip_prefix(base).unwrap().to_string_lossy().replace('\\', "/");
+            found.push((relative, fs::read_to_string(&path).unwrap()));
+        }
+    }
+}
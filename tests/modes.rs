@@ -0,0 +1,286 @@
+mod common;
+
+use common::Fixture;
+use workspace_gen::Mode;
+
+#[test]
+fn workspace_inherit_then_version_keeps_child_workspace_dependencies_loadable() {
+    let fix = Fixture::two_repos();
+    fix.run(Mode::WorkspaceInherit);
+    let inherited = fix.read("b/Cargo.bak.toml");
+    assert!(inherited.contains("a-core = { path = \"../a/a-core\" }"), "{}", inherited);
+    assert!(!inherited.contains("workspace = true"), "{}", inherited);
+
+    fix.run(Mode::Version);
+    let restored = fix.read("b/Cargo.toml");
+    assert!(!restored.contains("workspace = true"), "{}", restored);
+    assert!(restored.contains("a-core = \"0.1.0\""), "{}", restored);
+}
+
+#[test]
+fn workspace_inherit_then_version_restores_member_specs() {
+    let fix = Fixture::two_repos();
+    fix.run(Mode::WorkspaceInherit);
+    fix.run(Mode::Version);
+    let core = fix.read("b/b-core/Cargo.toml");
+    assert!(core.contains("a-util = \"0.1.0\"\n"), "{}", core);
+    // inherited before workspace-gen ever ran, so still inherited
+    assert!(core.contains("a-core = { workspace = true }\n"), "{}", core);
+    let util = fix.read("b/b-util/Cargo.toml");
+    assert!(util.contains("a-util = \"0.1.0\"\n"), "{}", util);
+    fix.cargo_metadata("b/b-core/Cargo.toml").unwrap();
+}
+
+fn inheriting_package(name: &str) -> String {
+    format!("[package]\nname = \"{}\"\nversion.workspace = true\nedition.workspace = true\n\
+             license-file.workspace = true\n", name)
+}
+
+#[test]
+fn local_path_moves_child_workspace_package_into_root() {
+    let fix = Fixture::new();
+    fix.repo("a", &[
+        ("Cargo.toml", "[workspace]\nmembers = [\"a-core\"]\n\n[workspace.package]\n\
+                        version = \"0.1.0\"\nedition = \"2021\"\nlicense-file = \"LICENSE\"\n\
+                        description = \"not inherited\"\n"),
+        ("LICENSE", ""),
+        ("a-core/Cargo.toml", &inheriting_package("a-core")),
+        ("a-core/src/lib.rs", ""),
+    ]);
+    fix.repo("b", &[
+        ("Cargo.toml", "[workspace]\nmembers = [\"b-core\"]\n"),
+        ("b-core/Cargo.toml", &common::package("b-core", "0.2.0", "a-core = \"0.1.0\"\n")),
+        ("b-core/src/lib.rs", ""),
+    ]);
+    fix.run(Mode::LocalPath);
+    let root = fix.read("Cargo.toml");
+    assert!(root.contains("[workspace.package]\nedition = \"2021\"\nlicense-file = \"a/LICENSE\"\n\
+                           version = \"0.1.0\"\n"), "{}", root);
+    assert!(!root.contains("description"), "{}", root);
+    fix.cargo_metadata("Cargo.toml").unwrap();
+    // read from the moved-aside Cargo.bak.toml this time
+    fix.run(Mode::LocalPath);
+    assert_eq!(fix.read("Cargo.toml"), root);
+}
+
+#[test]
+fn local_path_fails_when_child_workspace_packages_disagree() {
+    let fix = Fixture::new();
+    for (name, version) in [("a", "0.1.0"), ("b", "0.2.0")] {
+        fix.repo(name, &[
+            ("Cargo.toml", &format!("[workspace]\nmembers = [\"{0}-core\"]\n\n[workspace.package]\n\
+                                     version = \"{1}\"\nedition = \"2021\"\n\
+                                     license-file = \"LICENSE\"\n", name, version)),
+            ("LICENSE", ""),
+            (&format!("{}-core/Cargo.toml", name), &inheriting_package(&format!("{}-core", name))),
+            (&format!("{}-core/src/lib.rs", name), ""),
+        ]);
+    }
+    let err = workspace_gen::plan(&fix.opts(Mode::LocalPath)).err().unwrap();
+    let err = format!("{:#}", err);
+    assert!(err.contains("set package.license-file differently"), "{}", err);
+    assert!(!fix.exists("Cargo.toml"));
+}
+
+#[test]
+fn git_ref_finds_remotes_with_slashes_and_skips_stale_refs() {
+    let fix = Fixture::two_repos();
+    fix.remote("a", "team/fork", "https://example.com/team/a.git");
+    fix.remote_ref("a", "gone");
+    fix.remote("b", "origin", "https://example.com/b.git");
+    fix.run(Mode::GitRef);
+    let rev = git2::Repository::open(fix.path().join("a")).unwrap()
+        .head().unwrap().target().unwrap().to_string();
+    let util = fix.read("b/b-util/Cargo.toml");
+    let expected = format!("a-util = {{ git = \"https://example.com/team/a.git\", rev = \"{}\" }}", rev);
+    assert!(util.contains(&expected), "{}", util);
+}
+
+fn pushed_repos() -> Fixture {
+    let fix = Fixture::two_repos();
+    fix.remote("a", "origin", "https://example.com/a.git");
+    fix.remote("b", "origin", "https://example.com/b.git");
+    fix
+}
+
+#[test]
+fn git_ref_ignores_manifests_only_an_earlier_run_changed() {
+    let fix = pushed_repos();
+    fix.run(Mode::LocalPath);
+    let plan = workspace_gen::plan(&fix.opts(Mode::GitRef)).unwrap();
+    assert!(!plan.edits.is_empty());
+}
+
+#[test]
+fn git_ref_fails_on_other_manifest_changes() {
+    let fix = pushed_repos();
+    fix.run(Mode::LocalPath);
+    let util = fix.read("b/b-util/Cargo.toml");
+    fix.write("b/b-util/Cargo.toml", &util.replace("edition = \"2021\"", "edition = \"2018\""));
+    let err = workspace_gen::plan(&fix.opts(Mode::GitRef)).err().unwrap();
+    let err = format!("{:#}", err);
+    assert!(err.contains("Uncommitted changes"), "{}", err);
+    assert!(err.contains("/b"), "{}", err);
+    assert!(!err.contains("/a\n"), "{}", err);
+}
+
+#[test]
+fn undo_restores_rewritten_child_workspaces() {
+    let fix = Fixture::two_repos();
+    let before = fix.manifests();
+    fix.run(Mode::LocalPath);
+    workspace_gen::undo(fix.path(), false).unwrap();
+    assert_eq!(fix.manifests(), before);
+}
+
+#[test]
+fn undo_returns_what_it_put_back() {
+    let fix = Fixture::two_repos();
+    let before = fix.manifests();
+    fix.run(Mode::LocalPath);
+    let dry = workspace_gen::undo(fix.path(), true).unwrap();
+    assert_ne!(fix.manifests(), before);
+    let undone = workspace_gen::undo(fix.path(), false).unwrap();
+    assert_eq!(undone, dry);
+    assert_eq!(undone.last().unwrap().path, fix.path().join("Cargo.toml"));
+    assert!(undone.last().unwrap().removed);
+    assert!(undone[..undone.len() - 1].iter().all(|it| !it.removed && it.path.exists()));
+    assert_eq!(fix.manifests(), before);
+}
+
+#[test]
+fn restore_backups_returns_what_it_put_back() {
+    let fix = Fixture::two_repos();
+    let util = fix.read("b/b-util/Cargo.toml");
+    let mut opts = fix.opts(Mode::LocalPath);
+    opts.backup = true;
+    fix.run_with(&opts);
+    let dry = workspace_gen::restore_backups(fix.path(), true).unwrap();
+    assert_ne!(fix.read("b/b-util/Cargo.toml"), util);
+    let restored = workspace_gen::restore_backups(fix.path(), false).unwrap();
+    assert_eq!(restored, dry);
+    assert!(restored.iter().any(|it| it.path == fix.path().join("b/b-util/Cargo.toml")));
+    assert_eq!(fix.read("b/b-util/Cargo.toml"), util);
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_cycles_end_the_walk() {
+    let fix = Fixture::two_repos();
+    std::os::unix::fs::symlink(".", fix.path().join("a/a-core/itself")).unwrap();
+    std::os::unix::fs::symlink("../..", fix.path().join("b/b-core/up")).unwrap();
+    for follow_symlinks in [false, true] {
+        let mut opts = fix.opts(Mode::LocalPath);
+        opts.follow_symlinks = follow_symlinks;
+        let plan = workspace_gen::plan(&opts).unwrap();
+        let mut names: Vec<_> = plan.packages.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, ["a-core", "a-util", "b-core", "b-util"]);
+    }
+}
+
+#[test]
+fn features_survive_a_round_trip() {
+    let fix = Fixture::new();
+    fix.repo("a", &[
+        ("Cargo.toml", "[workspace]\nmembers = [\"a-util\"]\n"),
+        ("a-util/Cargo.toml", "[package]\nname = \"a-util\"\nversion = \"0.1.0\"\n\n\
+                               [features]\na = []\nb = []\n"),
+        ("a-util/src/lib.rs", ""),
+    ]);
+    let spec = "{ version = \"0.1.0\", default-features = false, features = [\"a\", \"b\"] }";
+    fix.repo("b", &[
+        ("Cargo.toml", "[workspace]\nmembers = [\"b-util\"]\n"),
+        ("b-util/Cargo.toml", &common::package("b-util", "0.2.0", &format!("a-util = {}\n", spec))),
+        ("b-util/src/lib.rs", ""),
+    ]);
+    fix.remote("a", "origin", "https://example.com/a.git");
+    fix.remote("b", "origin", "https://example.com/b.git");
+    let kept = ["default-features = false", "features = [\"a\", \"b\"]"];
+
+    fix.run(Mode::LocalPath);
+    let linked = fix.read("b/b-util/Cargo.toml");
+    assert!(linked.contains("path = \"../../a/a-util\""), "{}", linked);
+    assert!(kept.iter().all(|it| linked.contains(it)), "{}", linked);
+    fix.run(Mode::GitRef);
+    let pinned = fix.read("b/b-util/Cargo.toml");
+    assert!(pinned.contains("git = \"https://example.com/a.git\""), "{}", pinned);
+    assert!(kept.iter().all(|it| pinned.contains(it)), "{}", pinned);
+    fix.run(Mode::Version);
+    let restored: toml::Value = toml::from_str(&fix.read("b/b-util/Cargo.toml")).unwrap();
+    let original: toml::Value = toml::from_str(&format!("a-util = {}", spec)).unwrap();
+    assert_eq!(restored["dependencies"]["a-util"], original["a-util"]);
+}
+
+#[test]
+fn running_a_mode_twice_changes_nothing_more() {
+    let modes = [("local-path", Mode::LocalPath), ("workspace-inherit", Mode::WorkspaceInherit),
+                 ("git-ref", Mode::GitRef), ("version", Mode::Version)];
+    for (name, mode) in modes {
+        let fix = pushed_repos();
+        fix.run(mode);
+        let once = fix.manifests();
+        let plan = fix.run(mode);
+        assert!(plan.edits.iter().all(|it| it.before == it.after), "{}", name);
+        assert_eq!(plan.root_before.as_deref(), Some(plan.root_after.as_str()), "{}", name);
+        assert_eq!(fix.manifests(), once, "{}", name);
+    }
+}
+
+#[test]
+fn excludes_under_excluded_workspaces_fold_away() {
+    let fix = Fixture::two_repos();
+    fix.write("b/Cargo.toml", "[workspace]\nmembers = [\"b-core\", \"b-util\"]\n\
+                               exclude = [\"./vendor\", \"tools\"]\n\n\
+                               [workspace.dependencies]\na-core = \"0.1.0\"\n\
+                               b-util = { path = \"b-util\" }\n");
+    fix.write("b/tools/Cargo.toml", "[workspace]\nmembers = []\nexclude = [\"scratch\", \"../vendor\"]\n");
+    fix.commit("b", "nested workspace");
+    let plan = workspace_gen::plan(&fix.opts(Mode::LocalPath)).unwrap();
+    assert!(plan.root_after.contains("exclude = [\n    \"a\",\n    \"b\",\n]\n"), "{}", plan.root_after);
+}
+
+#[test]
+fn version_leaves_patches_pointing_elsewhere() {
+    let fix = Fixture::two_repos();
+    let patch = "\n[patch.crates-io]\na-core = { path = \"../../a/a-core\" }\n\
+                 a-util = { git = \"https://example.com/a.git\", branch = \"main\" }\n";
+    fix.write("b/b-util/Cargo.toml", &format!("{}{}", fix.read("b/b-util/Cargo.toml"), patch));
+    fix.commit("b", "patch a");
+    fix.run(Mode::LocalPath);
+    let linked = fix.read("b/b-util/Cargo.toml");
+    assert!(linked.contains("a-util = { path = \"../../a/a-util\" }\n\n[patch"), "{}", linked);
+    assert!(linked.ends_with("[patch.crates-io]\na-core = { path = \"../../a/a-core\" }\n\
+                              a-util = { path = \"../../a/a-util\" }\n"), "{}", linked);
+    fix.run(Mode::Version);
+    let restored = fix.read("b/b-util/Cargo.toml");
+    assert!(restored.ends_with(patch), "{}", restored);
+}
+
+#[test]
+fn exclude_globs_match_manifests_too() {
+    let fix = Fixture::two_repos();
+    fix.repo("vendor", &[
+        ("Cargo.toml", &common::package("vendored", "1.0.0", "")),
+        ("src/lib.rs", ""),
+    ]);
+    let mut opts = fix.opts(Mode::LocalPath);
+    opts.exclude = vec!["vendor/**".to_string()];
+    let plan = workspace_gen::plan(&opts).unwrap();
+    assert!(!plan.tomls.contains_key("vendored"), "{:?}", plan.tomls);
+    assert!(!plan.root_after.contains("\"vendor\""), "{}", plan.root_after);
+}
+
+#[test]
+fn release_pushes_to_the_pinned_remote() {
+    let fix = pushed_repos();
+    fix.remote("a", "fork", "https://example.com/fork/a.git");
+    fix.remote("b", "fork", "https://example.com/fork/b.git");
+    let mut opts = fix.opts(Mode::Release);
+    let remotes = |opts: &workspace_gen::GenerateOptions| -> Vec<_> {
+        workspace_gen::release_order(opts).unwrap().into_iter().map(|it| it.remote).collect()
+    };
+    assert_eq!(remotes(&opts), ["origin", "origin"]);
+    opts.remote = Some("fork".to_string());
+    assert_eq!(remotes(&opts), ["fork", "fork"]);
+}